@@ -3,6 +3,8 @@ extern crate libc;
 extern crate num;
 extern crate time;
 extern crate termbox_sys as termbox;
+extern crate unicode_segmentation;
+extern crate unicode_width;
 #[macro_use] extern crate bitflags;
 
 pub use self::style::{Style, RB_BOLD, RB_UNDERLINE, RB_REVERSE, RB_NORMAL};
@@ -10,15 +12,25 @@ pub use self::style::{Style, RB_BOLD, RB_UNDERLINE, RB_REVERSE, RB_NORMAL};
 use std::error::Error;
 use std::fmt;
 use std::io;
+use std::io::Write;
 use std::char;
 use std::default::Default;
 use std::marker::PhantomData;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::mem;
+use std::slice;
+use std::sync::atomic::{self, AtomicUsize, AtomicBool, Ordering};
+use std::sync::{Arc, mpsc};
+use std::thread;
 
 use num::FromPrimitive;
 use termbox::RawEvent;
 use libc::c_int;
 use gag::Hold;
 use time::Duration;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
 pub mod keyboard;
 pub mod mouse;
@@ -27,21 +39,40 @@ pub use self::running::running;
 pub use keyboard::Key;
 pub use mouse::Mouse;
 
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 /// Dictates the type of an event that has been recieved.
 pub enum Event {
     /// A raw, non-wrapped key event
     KeyEventRaw(u8, u16, u32),
-    /// A key event with the key code and information transformed into an optional `Key`
-    KeyEvent(Option<Key>),
+    /// A key event with the key code and information transformed into an optional `Key`,
+    /// along with the decoded modifier keys (Alt, etc.) that were held when it fired
+    KeyEvent(Option<Key>, Mods),
     /// A window resize event, with the new width and height
     ResizeEvent(i32, i32),
     /// A mouse event, with the type of the event, and the x and y coordinates
     MouseEvent(Mouse, i32, i32),
+    /// A block of text pasted while bracketed-paste mode (`InitOptions::enable_paste`) was on
+    PasteEvent(String),
     /// An empty event
     NoEvent
 }
 
+mod mods {
+    bitflags! {
+        #[repr(C)]
+        /// The modifier keys that can accompany a key or mouse event, decoded from
+        /// termbox's raw `emod` bitmask (`TB_MOD_*`).
+        flags Mods: u8 {
+            /// The Alt key was held when this event fired.
+            const MOD_ALT = 0x01,
+            /// Mouse motion (a drag) rather than a discrete click or key press.
+            const MOD_MOTION = 0x02,
+        }
+    }
+}
+
+pub use self::mods::{Mods, MOD_ALT, MOD_MOTION};
+
 #[derive(Clone, Copy, Debug)]
 /// The mode of the input
 pub enum InputMode {
@@ -59,18 +90,121 @@ pub enum InputMode {
 }
 
 #[derive(Clone, Copy, PartialEq)]
-#[repr(C,u16)]
 /// The supported colors for Rustbox
 pub enum Color {
-    Default =  0x00,
-    Black =    0x01,
-    Red =      0x02,
-    Green =    0x03,
-    Yellow =   0x04,
-    Blue =     0x05,
-    Magenta =  0x06,
-    Cyan =     0x07,
-    White =    0x08,
+    Default,
+    Black,
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
+    Cyan,
+    White,
+    /// A color from the terminal's extended palette, as selected by `OutputMode`.
+    ///
+    /// In `Output256` mode, 1-216 are the 6x6x6 color cube and 217-255 are a
+    /// grayscale ramp; `Output216` and `Grayscale` each address one of those
+    /// ranges directly, re-based to start at 0. In `Normal` mode only the low 4 bits
+    /// of the index are used.
+    Indexed(u16),
+}
+
+impl Color {
+    fn as_u16(&self) -> u16 {
+        match *self {
+            Color::Default => 0x00,
+            Color::Black => 0x01,
+            Color::Red => 0x02,
+            Color::Green => 0x03,
+            Color::Yellow => 0x04,
+            Color::Blue => 0x05,
+            Color::Magenta => 0x06,
+            Color::Cyan => 0x07,
+            Color::White => 0x08,
+            Color::Indexed(i) => i,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+/// Selects how many colors termbox will use to render the screen.
+///
+/// Passed to `RustBox::set_output_mode`, which wraps `tb_select_output_mode`.
+pub enum OutputMode {
+    /// The default set of 8 ANSI colors (and their bold variants).
+    Normal = 1,
+    /// Termbox's 256-color palette: the 6x6x6 color cube plus a grayscale ramp.
+    Output256 = 2,
+    /// The 216-color subset of `Output256`, covering just the color cube.
+    Output216 = 3,
+    /// A grayscale-only ramp, for terminals that don't support color.
+    Grayscale = 4,
+}
+
+impl OutputMode {
+    fn from_u16(n: u16) -> OutputMode {
+        match n {
+            2 => OutputMode::Output256,
+            3 => OutputMode::Output216,
+            4 => OutputMode::Grayscale,
+            _ => OutputMode::Normal,
+        }
+    }
+}
+
+// Termbox is a singleton (see `running`), so the active output mode is global
+// state too; this lets `Style::from_color` decide how to mask a `Color`
+// without threading the mode through every call site.
+static CURRENT_OUTPUT_MODE: AtomicUsize = atomic::ATOMIC_USIZE_INIT;
+
+fn current_output_mode() -> OutputMode {
+    OutputMode::from_u16(CURRENT_OUTPUT_MODE.load(atomic::Ordering::SeqCst) as u16)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(C)]
+/// A single screen cell: a code point plus foreground/background attributes.
+///
+/// Laid out to match termbox's own cell struct so that `RustBox::cell_buffer`/
+/// `cell_buffer_mut` can hand out a view directly onto `tb_cell_buffer` instead of copying.
+pub struct Cell {
+    pub ch: char,
+    pub fg: u16,
+    pub bg: u16,
+}
+
+/// Compares `current` against `shadow` cell-by-cell, updating `shadow` to match and
+/// returning how many cells differed. Factored out of `present_diff` so the diffing
+/// logic can be exercised without a real termbox screen.
+fn diff_and_update_shadow(current: &[Cell], shadow: &mut [Cell]) -> usize {
+    let mut changed = 0;
+    for (c, s) in current.iter().zip(shadow.iter_mut()) {
+        if c != s {
+            changed += 1;
+            *s = *c;
+        }
+    }
+    changed
+}
+
+/// Walks `s` grapheme cluster by grapheme cluster, returning the base char and display
+/// width to draw for each non-zero-width cluster (combining marks are dropped, since
+/// termbox only stores one code point per cell). Factored out of `print` so the layout
+/// logic can be exercised without a real termbox screen.
+fn grapheme_columns(s: &str) -> Vec<(char, usize)> {
+    s.graphemes(true)
+        .filter_map(|grapheme| {
+            let width = grapheme.width();
+            if width == 0 {
+                // A combining mark or other zero-width cluster: leave the base cell
+                // alone and don't advance the cursor for it.
+                return None;
+            }
+            let ch = grapheme.chars().next().unwrap_or(' ');
+            Some((ch, width))
+        })
+        .collect()
 }
 
 mod style {
@@ -95,7 +229,14 @@ mod style {
     impl Style {
         /// Converts a `Color` to a `Style` (`u64`)
         pub fn from_color(color: super::Color) -> Style {
-            Style { bits: color as u16 & TB_NORMAL_COLOR.bits }
+            let value = color.as_u16();
+            if super::current_output_mode() == super::OutputMode::Normal {
+                Style { bits: value & TB_NORMAL_COLOR.bits }
+            } else {
+                // In a 256/216/grayscale mode, termbox reads the full index out of
+                // the low byte instead of just the bottom 4 bits, so don't mask it.
+                Style { bits: value }
+            }
         }
     }
 }
@@ -110,6 +251,9 @@ pub enum EventError {
     TermboxError,
     /// An unknown event occured
     Unknown(isize),
+    /// A live `EventStream` owns termbox's input syscalls right now, so `poll_event`/
+    /// `peek_event` can't be called concurrently with it.
+    StreamActive,
 }
 
 impl fmt::Display for EventError {
@@ -125,6 +269,7 @@ impl Error for EventError {
          // I don't know how to format this without lifetime error.
          // EventError::Unknown(n) => &format!("There was an unknown error. Error code: {}", n),
          EventError::Unknown(_) => "Unknown error in Termbox",
+         EventError::StreamActive => "An EventStream is already polling termbox",
       }
    }
 }
@@ -164,7 +309,8 @@ fn unpack_event(ev_type: c_int, ev: &RawEvent, raw: bool) -> EventResult {
                     0 => char::from_u32(ev.ch).map(|c| Key::Char(c)),
                     a => Key::from_code(a),
                 };
-                Event::KeyEvent(k)
+                let mods = Mods::from_bits_truncate(ev.emod);
+                Event::KeyEvent(k, mods)
             }),
         2 => Ok(Event::ResizeEvent(ev.w, ev.h)),
         3 => {
@@ -176,6 +322,52 @@ fn unpack_event(ev_type: c_int, ev: &RawEvent, raw: bool) -> EventResult {
     }
 }
 
+/// Runs a raw termbox event through the bracketed-paste state machine (when `paste_enabled`)
+/// before falling back to the normal `unpack_event` decoding.
+///
+/// Takes `paste`/`replay` by `&mut` reference rather than through `&RustBox` so that both
+/// `RustBox::process_event` (backed by its `RefCell`s) and `EventStream`'s worker thread
+/// (which can't touch a `RustBox` across threads, since it's `!Send`) can share this logic
+/// against their own independently-owned state.
+fn decode_event(
+    paste_enabled: bool,
+    paste: &mut PasteState,
+    replay: &mut VecDeque<char>,
+    rc: c_int,
+    ev: &RawEvent,
+    raw: bool,
+) -> EventResult {
+    if paste_enabled && rc == 1 {
+        // Termbox reports a bare/unmatched ESC as the special key `TB_KEY_ESC`
+        // (`ev.key == 0x1b`) rather than as a char event (see `InputMode`'s doc on
+        // `Esc`/`Alt`), and a bracketed-paste marker always starts with exactly that
+        // byte, so feed it into the state machine the same as any other char. Inside a
+        // paste, the other single-byte keys termbox reports as special keys (space,
+        // Enter, Tab, other control bytes, backspace: all below 0x80) are pasted text too.
+        const TB_KEY_ESC: u16 = 0x1b;
+        let ch = if ev.key == 0 {
+            char::from_u32(ev.ch)
+        } else if ev.key == TB_KEY_ESC || (paste.active && ev.key < 0x80) {
+            char::from_u32(ev.key as u32)
+        } else {
+            None
+        };
+        if let Some(ch) = ch {
+            match paste.feed(ch) {
+                PasteFeed::Complete(text) => return Ok(Event::PasteEvent(text)),
+                PasteFeed::Consumed => return Ok(Event::NoEvent),
+                PasteFeed::Recovered(mut chars) => {
+                    let first = chars.remove(0);
+                    replay.extend(chars);
+                    return Ok(Event::KeyEvent(Some(Key::Char(first)), Mods::empty()));
+                }
+                PasteFeed::Unrelated => {}
+            }
+        }
+    }
+    unpack_event(rc, ev, raw)
+}
+
 #[derive(Debug)]
 /// Represents the kinds of errors that can occur when initializing Rustbox.
 pub enum InitError {
@@ -240,6 +432,22 @@ impl FromPrimitive for InitError {
 pub struct RustBox {
     // We only bother to redirect stderr for the moment, since it's used for panic!
     _stderr: Option<Hold>,
+    // Whether bracketed paste was enabled on init, and so needs disabling on drop.
+    paste_enabled: bool,
+    // Partial state for the bracketed-paste marker state machine; persists across
+    // `poll_event`/`peek_event` calls since a paste can straddle many of them.
+    paste: RefCell<PasteState>,
+    // Characters recovered from a false-start paste-marker match (see `PasteFeed::Recovered`)
+    // that still need to be delivered as ordinary key events; drained one at a time, since
+    // `process_event` can only hand back a single `Event` per call.
+    replay: RefCell<VecDeque<char>>,
+    // The width and cell contents of the last-presented frame, used by `present_diff`
+    // to skip no-op frames. An empty shadow forces a full diff (and thus a `present`)
+    // the first time; `present_diff` re-checks both fields on every call (rather than
+    // relying on spotting a `ResizeEvent`, which a caller driving input through
+    // `EventStream` instead of `poll_event`/`peek_event` would never see) so it always
+    // reallocates to fit the current dimensions.
+    shadow: RefCell<(usize, Vec<Cell>)>,
     // RAII lock.
     //
     // Note that running *MUST* be the last field in the destructor, since destructors run in
@@ -265,6 +473,13 @@ pub struct InitOptions {
     /// your program, don't use RustBox's default pipe-based redirection; instead, redirect stderr
     /// to a log file or another process that is capable of handling it better.
     pub buffer_stderr: bool,
+
+    /// Use this option to turn on bracketed-paste mode. When enabled, a block of text pasted
+    /// into the terminal is delivered as a single `Event::PasteEvent(String)` instead of a
+    /// flood of individual `KeyEvent`s, so typed and pasted input can be told apart. This
+    /// applies equally whether events are read through `RustBox::poll_event`/`peek_event` or
+    /// through an `EventStream` — both run the same bracketed-paste state machine.
+    pub enable_paste: bool,
 }
 
 impl Default for InitOptions {
@@ -273,6 +488,81 @@ impl Default for InitOptions {
         InitOptions {
             input_mode: InputMode::Current,
             buffer_stderr: false,
+            enable_paste: false,
+        }
+    }
+}
+
+// The two delimiters a terminal wraps a pasted block in when bracketed paste
+// (`ESC [ ? 2004 h`) is enabled.
+const PASTE_START: [char; 6] = ['\u{1b}', '[', '2', '0', '0', '~'];
+const PASTE_END: [char; 6] = ['\u{1b}', '[', '2', '0', '1', '~'];
+
+/// Result of feeding one character through the bracketed-paste state machine.
+#[derive(Debug)]
+enum PasteFeed {
+    /// Not part of any paste marker; handle the fed character as a normal key event.
+    Unrelated,
+    /// Consumed into an in-progress marker or the paste buffer.
+    Consumed,
+    /// The end marker just completed; here's the pasted text.
+    Complete(String),
+    /// A marker match started and then diverged before completing. These characters
+    /// (in order) were tentatively swallowed as a possible marker but turned out not to
+    /// be one, and must be replayed as ordinary key events instead of being dropped.
+    Recovered(Vec<char>),
+}
+
+#[derive(Default)]
+struct PasteState {
+    /// True once the start marker has fully matched and we're buffering pasted text.
+    active: bool,
+    /// Accumulated pasted text, once `active`.
+    buffer: String,
+    /// How far into the marker we're currently expecting (start or end) we've matched.
+    progress: usize,
+}
+
+impl PasteState {
+    fn feed(&mut self, ch: char) -> PasteFeed {
+        let marker = if self.active { &PASTE_END } else { &PASTE_START };
+        if ch == marker[self.progress] {
+            self.progress += 1;
+            if self.progress == marker.len() {
+                self.progress = 0;
+                if self.active {
+                    self.active = false;
+                    return PasteFeed::Complete(mem::replace(&mut self.buffer, String::new()));
+                }
+                self.active = true;
+            }
+            return PasteFeed::Consumed;
+        }
+        if self.progress == 0 {
+            return if self.active {
+                self.buffer.push(ch);
+                PasteFeed::Consumed
+            } else {
+                PasteFeed::Unrelated
+            };
+        }
+        // We matched part of a marker and then the sequence diverged (e.g. a lone ESC
+        // that wasn't actually the start of a paste). If we were mid-paste, what we'd
+        // tentatively matched against the end marker belongs in the buffer, not on the
+        // floor; a lone ESC here is deliberately *not* treated as a paste terminator.
+        let mut matched: Vec<char> = marker[..self.progress].iter().cloned().collect();
+        self.progress = 0;
+        if self.active {
+            for &c in &matched {
+                self.buffer.push(c);
+            }
+            self.buffer.push(ch);
+            PasteFeed::Consumed
+        } else {
+            // Not mid-paste, so these were never anything but ordinary keystrokes;
+            // give them all back instead of dropping the ones already matched.
+            matched.push(ch);
+            PasteFeed::Recovered(matched)
         }
     }
 }
@@ -317,6 +607,140 @@ mod running {
     }
 }
 
+mod stream {
+    use std::sync::atomic::{self, AtomicBool};
+
+    // Mirrors `running::RUSTBOX_RUNNING`: termbox's input syscalls aren't thread-safe,
+    // so at most one `EventStream` (the only thing allowed to poll from its own thread)
+    // may exist at a time.
+    static STREAM_RUNNING: AtomicBool = atomic::ATOMIC_BOOL_INIT;
+
+    /// True while an `EventStream` is alive. `RustBox::poll_event`/`peek_event` check
+    /// this so they refuse to race the stream's worker thread for termbox's input.
+    pub fn running() -> bool {
+        STREAM_RUNNING.load(atomic::Ordering::SeqCst)
+    }
+
+    #[allow(missing_copy_implementations)]
+    pub struct StreamGuard(());
+
+    pub fn run() -> Option<StreamGuard> {
+        if STREAM_RUNNING.swap(true, atomic::Ordering::SeqCst) {
+            None
+        } else {
+            Some(StreamGuard(()))
+        }
+    }
+
+    impl Drop for StreamGuard {
+        fn drop(&mut self) {
+            STREAM_RUNNING.store(false, atomic::Ordering::SeqCst);
+        }
+    }
+}
+
+/// A background event source that polls termbox on a dedicated worker thread.
+///
+/// This is useful for folding rustbox's input into an external event loop that also
+/// waits on other sources (sockets, timers, ...), instead of blocking the whole loop
+/// inside `RustBox::poll_event`. While a stream is alive it owns termbox's input
+/// syscalls exclusively: it borrows the `RustBox` for its whole lifetime, so the
+/// borrow checker keeps termbox alive (and `tb_shutdown` from running) for as long as
+/// the stream exists, and `RustBox::poll_event`/`peek_event` refuse to run
+/// concurrently with it. Only one `EventStream` may exist at a time.
+///
+/// Bracketed paste (`InitOptions::enable_paste`) is honored here too: the worker thread
+/// runs the same paste state machine as `poll_event`/`peek_event`, against its own
+/// independent paste/replay state (safe since the two never run concurrently), so a
+/// paste shows up as a single `Event::PasteEvent(String)` regardless of which way you're
+/// reading events.
+pub struct EventStream<'a> {
+    rx: mpsc::Receiver<EventResult>,
+    stop: Arc<AtomicBool>,
+    _guard: stream::StreamGuard,
+    worker: Option<thread::JoinHandle<()>>,
+    _rb: PhantomData<&'a RustBox>,
+}
+
+impl<'a> EventStream<'a> {
+    /// Spawns the worker thread backing this stream. Returns `None` if an
+    /// `EventStream` already exists.
+    pub fn new(rb: &'a RustBox) -> Option<EventStream<'a>> {
+        // `stream::running()` (checked by `poll_event`/`peek_event`) guarantees `rb`'s own
+        // paste state sits idle for as long as this stream is alive, so the worker thread
+        // below is free to run the same bracketed-paste state machine against its own
+        // independently-owned `PasteState`/replay queue instead (it can't touch `rb` itself
+        // across threads, since `RustBox` is `!Send`).
+        let paste_enabled = rb.paste_enabled;
+        let guard = match stream::run() {
+            Some(g) => g,
+            None => return None,
+        };
+
+        let (tx, rx) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let worker_stop = stop.clone();
+
+        let worker = thread::spawn(move || {
+            let mut paste = PasteState::default();
+            let mut replay: VecDeque<char> = VecDeque::new();
+            while !worker_stop.load(Ordering::SeqCst) {
+                if let Some(ch) = replay.pop_front() {
+                    let result = Ok(Event::KeyEvent(Some(Key::Char(ch)), Mods::empty()));
+                    if tx.send(result).is_err() {
+                        break;
+                    }
+                    continue;
+                }
+                let mut ev = NIL_RAW_EVENT;
+                // A short timeout so we come back around and re-check `worker_stop`
+                // instead of blocking forever in `tb_poll_event`.
+                let rc = unsafe { termbox::tb_peek_event(&mut ev, 100) };
+                if rc == 0 {
+                    continue;
+                }
+                let result = decode_event(paste_enabled, &mut paste, &mut replay, rc, &ev, false);
+                if tx.send(result).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Some(EventStream {
+            rx: rx,
+            stop: stop,
+            _guard: guard,
+            worker: Some(worker),
+            _rb: PhantomData,
+        })
+    }
+
+    /// Returns the next buffered event without blocking, if one is available.
+    pub fn try_recv(&self) -> Option<EventResult> {
+        match self.rx.try_recv() {
+            Ok(ev) => Some(ev),
+            Err(_) => None,
+        }
+    }
+}
+
+impl<'a> Iterator for EventStream<'a> {
+    type Item = EventResult;
+
+    fn next(&mut self) -> Option<EventResult> {
+        self.rx.recv().ok()
+    }
+}
+
+impl<'a> Drop for EventStream<'a> {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
 impl RustBox {
     /// Initialize Rustbox.
     ///
@@ -351,6 +775,10 @@ impl RustBox {
         let rb = unsafe { match termbox::tb_init() {
             0 => RustBox {
                 _stderr: stderr,
+                paste_enabled: opts.enable_paste,
+                paste: RefCell::new(PasteState::default()),
+                replay: RefCell::new(VecDeque::new()),
+                shadow: RefCell::new((0, Vec::new())),
                 _running: running,
                 _phantom: PhantomData,
             },
@@ -362,6 +790,10 @@ impl RustBox {
             InputMode::Current => (),
             _ => rb.set_input_mode(opts.input_mode),
         }
+        if opts.enable_paste {
+            let _ = io::stdout().write_all(b"\x1b[?2004h");
+            let _ = io::stdout().flush();
+        }
         Ok(rb)
     }
 
@@ -385,6 +817,23 @@ impl RustBox {
         unsafe { termbox::tb_present() }
     }
 
+    /// Like `present`, but skips the call entirely if nothing has changed since the last
+    /// `present_diff`, by comparing the current back buffer against a shadow copy of the last
+    /// presented frame. Returns the number of cells that had changed.
+    pub fn present_diff(&self) -> usize {
+        let current = self.cell_buffer();
+        let width = self.width();
+        let mut shadow = self.shadow.borrow_mut();
+        if shadow.0 != width || shadow.1.len() != current.len() {
+            *shadow = (width, vec![Cell { ch: '\0', fg: 0, bg: 0 }; current.len()]);
+        }
+        let changed = diff_and_update_shadow(current, &mut shadow.1);
+        if changed > 0 {
+            self.present();
+        }
+        changed
+    }
+
     /// Changes the position of the user's cursor.
     pub fn set_cursor(&self, x: isize, y: isize) {
         unsafe { termbox::tb_set_cursor(x as c_int, y as c_int) }
@@ -395,15 +844,68 @@ impl RustBox {
         termbox::tb_change_cell(x as c_int, y as c_int, ch, fg, bg)
     }
 
+    /// Returns a read-only view of the termbox back buffer: the grid of cells that will be
+    /// drawn to the terminal on the next `present`, indexed by `width() * y + x`.
+    ///
+    /// `print`/`print_char`/`set_cell` only ever write a `char` into termbox's cell buffer,
+    /// and `change_cell` is `unsafe`, leaving it to its callers to pass a valid Unicode
+    /// scalar value, so reinterpreting its code points
+    /// as `char` here is sound in practice even though `Cell`'s layout can't be checked
+    /// against termbox's C struct at compile time.
+    pub fn cell_buffer(&self) -> &[Cell] {
+        let len = self.width() * self.height();
+        unsafe { slice::from_raw_parts(termbox::tb_cell_buffer() as *const Cell, len) }
+    }
+
+    /// Same as `cell_buffer`, but mutable, for direct retained-mode drawing.
+    ///
+    /// # Safety
+    /// Termbox's cell buffer is a single global resource with no borrow checking of its
+    /// own: the caller must not let this slice's lifetime overlap with another call to
+    /// `cell_buffer` or `cell_buffer_mut`, or two references (shared or exclusive) would
+    /// alias the same memory.
+    pub unsafe fn cell_buffer_mut(&self) -> &mut [Cell] {
+        let len = self.width() * self.height();
+        slice::from_raw_parts_mut(termbox::tb_cell_buffer() as *mut Cell, len)
+    }
+
+    /// Sets a single cell by (x, y) directly, bypassing `print`'s styling/width handling.
+    ///
+    /// Goes through `change_cell` rather than `cell_buffer_mut`, so unlike that function
+    /// this one stays safe: it never hands out a Rust reference into termbox's cell
+    /// buffer, so it can't alias a live `cell_buffer()`/`cell_buffer_mut()` slice.
+    pub fn set_cell(&self, x: usize, y: usize, cell: Cell) {
+        unsafe {
+            self.change_cell(x, y, cell.ch as u32, cell.fg, cell.bg);
+        }
+    }
+
     /// Prints a string-slice to the screen at x and y, with a style, foreground, and background.
-    pub fn print(&self, x: usize, y: usize, sty: Style, fg: Color, bg: Color, s: &str) {
+    ///
+    /// The string is walked grapheme cluster by grapheme cluster rather than char by char, so
+    /// that wide East-Asian characters and emoji occupy two cells and combining marks share
+    /// the cell of the character they attach to instead of each claiming one of their own
+    /// (only that base character is drawn, since termbox stores one code point per cell).
+    /// Returns the number of
+    /// columns actually consumed, so callers can lay out whatever comes after `s`.
+    pub fn print(&self, x: usize, y: usize, sty: Style, fg: Color, bg: Color, s: &str) -> usize {
         let fg = Style::from_color(fg) | (sty & style::TB_ATTRIB);
         let bg = Style::from_color(bg);
-        for (i, ch) in s.chars().enumerate() {
+        let mut col = 0;
+        for (ch, width) in grapheme_columns(s) {
             unsafe {
-                self.change_cell(x+i, y, ch as u32, fg.bits(), bg.bits());
+                self.change_cell(x + col, y, ch as u32, fg.bits(), bg.bits());
             }
+            // A wide cluster occupies extra cells; blank them so stale content
+            // doesn't peek out from beside the wide glyph.
+            for i in 1..width {
+                unsafe {
+                    self.change_cell(x + col + i, y, ' ' as u32, fg.bits(), bg.bits());
+                }
+            }
+            col += width;
         }
+        col
     }
 
     /// Same as `print` but a single character instead of an entire string.
@@ -416,21 +918,57 @@ impl RustBox {
     }
 
     /// Asks Rustbox if there is an event, and if there is, returns it.
+    ///
+    /// Returns `Err(EventError::StreamActive)` instead of polling if a background
+    /// `EventStream` currently owns termbox's input syscalls.
     pub fn poll_event(&self, raw: bool) -> EventResult {
+        if stream::running() {
+            return Err(EventError::StreamActive);
+        }
+        // Deliver any keystrokes recovered from a previous false-start paste-marker match
+        // before polling termbox for a new one, so a live event (the one `tb_poll_event`
+        // would otherwise return right now) never gets thrown away just to make room for
+        // a queued one.
+        if let Some(ch) = self.replay.borrow_mut().pop_front() {
+            return Ok(Event::KeyEvent(Some(Key::Char(ch)), Mods::empty()));
+        }
         let mut ev = NIL_RAW_EVENT;
         let rc = unsafe {
             termbox::tb_poll_event(&mut ev)
         };
-        unpack_event(rc, &ev, raw)
+        self.process_event(rc, &ev, raw)
     }
 
     /// Waits a certain amount of time before performing a `poll`.
+    ///
+    /// Returns `Err(EventError::StreamActive)` instead of polling if a background
+    /// `EventStream` currently owns termbox's input syscalls.
     pub fn peek_event(&self, timeout: Duration, raw: bool) -> EventResult {
+        if stream::running() {
+            return Err(EventError::StreamActive);
+        }
+        // See the matching comment in `poll_event`.
+        if let Some(ch) = self.replay.borrow_mut().pop_front() {
+            return Ok(Event::KeyEvent(Some(Key::Char(ch)), Mods::empty()));
+        }
         let mut ev = NIL_RAW_EVENT;
         let rc = unsafe {
             termbox::tb_peek_event(&mut ev, timeout.num_milliseconds() as c_int)
         };
-        unpack_event(rc, &ev, raw)
+        self.process_event(rc, &ev, raw)
+    }
+
+    /// Runs a raw termbox event through the bracketed-paste state machine (when enabled)
+    /// before falling back to the normal `unpack_event` decoding.
+    fn process_event(&self, rc: c_int, ev: &RawEvent, raw: bool) -> EventResult {
+        decode_event(
+            self.paste_enabled,
+            &mut self.paste.borrow_mut(),
+            &mut self.replay.borrow_mut(),
+            rc,
+            ev,
+            raw,
+        )
     }
 
     /// Changes the input mode.
@@ -439,11 +977,30 @@ impl RustBox {
             termbox::tb_select_input_mode(mode as c_int);
         }
     }
+
+    /// Changes the output mode, i.e. how many colors are available to draw with.
+    ///
+    /// See `OutputMode` for the available palettes.
+    pub fn set_output_mode(&self, mode: OutputMode) {
+        unsafe {
+            termbox::tb_select_output_mode(mode as c_int);
+        }
+        CURRENT_OUTPUT_MODE.store(mode as usize, atomic::Ordering::SeqCst);
+    }
+
+    /// Returns the currently active output mode.
+    pub fn output_mode(&self) -> OutputMode {
+        current_output_mode()
+    }
 }
 
 impl Drop for RustBox {
     /// Shuts down a Rustbox instance.
     fn drop(&mut self) {
+        if self.paste_enabled {
+            let _ = io::stdout().write_all(b"\x1b[?2004l");
+            let _ = io::stdout().flush();
+        }
         // Since only one instance of the RustBox is ever accessible, we should not
         // need to do this atomically.
         // Note: we should definitely have RUSTBOX_RUNNING = true here.
@@ -452,3 +1009,146 @@ impl Drop for RustBox {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_and_update_shadow_counts_and_applies_changes() {
+        let blank = Cell { ch: ' ', fg: 0, bg: 0 };
+        let a = Cell { ch: 'a', fg: 1, bg: 0 };
+        let b = Cell { ch: 'b', fg: 2, bg: 0 };
+
+        let current = vec![a, blank, b];
+        let mut shadow = vec![blank, blank, blank];
+
+        assert_eq!(diff_and_update_shadow(&current, &mut shadow), 2);
+        assert_eq!(shadow, current);
+
+        // A second diff against the now-matching shadow finds nothing left to update.
+        assert_eq!(diff_and_update_shadow(&current, &mut shadow), 0);
+    }
+
+    #[test]
+    fn style_from_color_masks_indexed_colors_by_output_mode() {
+        // `Style::from_color`/`current_output_mode` read this static directly rather
+        // than taking the mode as a parameter, so drive it the same way
+        // `RustBox::set_output_mode` does and restore it once we're done.
+        CURRENT_OUTPUT_MODE.store(OutputMode::Normal as usize, atomic::Ordering::SeqCst);
+        let normal = Style::from_color(Color::Indexed(0xab));
+        assert_eq!(normal.bits(), 0xab & 0x000F);
+
+        CURRENT_OUTPUT_MODE.store(OutputMode::Output256 as usize, atomic::Ordering::SeqCst);
+        let indexed = Style::from_color(Color::Indexed(0xab));
+        assert_eq!(indexed.bits(), 0xab);
+
+        CURRENT_OUTPUT_MODE.store(OutputMode::Normal as usize, atomic::Ordering::SeqCst);
+    }
+
+    #[test]
+    fn unpack_event_decodes_alt_modifier() {
+        let ev = RawEvent {
+            etype: 1,
+            emod: MOD_ALT.bits(),
+            key: 0,
+            ch: 'a' as u32,
+            w: 0,
+            h: 0,
+            x: 0,
+            y: 0,
+        };
+
+        match unpack_event(1, &ev, false) {
+            Ok(Event::KeyEvent(Some(Key::Char(c)), mods)) => {
+                assert_eq!(c, 'a');
+                assert!(mods.contains(MOD_ALT));
+                assert!(!mods.contains(MOD_MOTION));
+            }
+            other => panic!("expected a KeyEvent with MOD_ALT set, got {:?}", other.is_ok()),
+        }
+    }
+
+    #[test]
+    fn grapheme_columns_handles_wide_and_combining_clusters() {
+        // A plain ASCII char, a double-width CJK char, and an emoji.
+        assert_eq!(grapheme_columns("a"), vec![('a', 1)]);
+        assert_eq!(grapheme_columns("漢"), vec![('漢', 2)]);
+        assert_eq!(grapheme_columns("🎉"), vec![('🎉', 2)]);
+
+        // A base char with a combining accent forms one grapheme cluster that should
+        // draw as the base char occupying a single cell, not two separate cells.
+        assert_eq!(grapheme_columns("e\u{0301}"), vec![('e', 1)]);
+
+        assert_eq!(
+            grapheme_columns("a漢b"),
+            vec![('a', 1), ('漢', 2), ('b', 1)]
+        );
+    }
+
+    #[test]
+    #[ignore] // needs a real TTY: tb_init fails headless (e.g. in CI); run manually with --ignored
+    fn event_stream_create_and_drop_is_clean() {
+        let rb = RustBox::init(Default::default()).expect("init rustbox");
+
+        {
+            let stream = EventStream::new(&rb).expect("create stream");
+            drop(stream);
+        }
+
+        // Dropping the first stream joins its worker thread (a worker that never stopped
+        // would hang the drop above) and then releases its StreamGuard; had the guard not
+        // been released, this would return None instead.
+        let stream2 = EventStream::new(&rb);
+        assert!(stream2.is_some());
+    }
+
+    #[test]
+    fn paste_state_reassembles_pasted_text() {
+        let mut paste = PasteState::default();
+        let mut text = String::new();
+        let mut saw_complete = false;
+
+        for &marker_ch in PASTE_START.iter() {
+            match paste.feed(marker_ch) {
+                PasteFeed::Consumed => {}
+                other => panic!("unexpected PasteFeed variant: {:?}", other),
+            }
+        }
+        for ch in "hello\nworld".chars() {
+            match paste.feed(ch) {
+                PasteFeed::Consumed => {}
+                other => panic!("unexpected PasteFeed variant: {:?}", other),
+            }
+        }
+        for (i, &marker_ch) in PASTE_END.iter().enumerate() {
+            match paste.feed(marker_ch) {
+                PasteFeed::Complete(s) => {
+                    assert_eq!(i, PASTE_END.len() - 1);
+                    text = s;
+                    saw_complete = true;
+                }
+                PasteFeed::Consumed => assert!(i < PASTE_END.len() - 1),
+                other => panic!("unexpected PasteFeed variant: {:?}", other),
+            }
+        }
+
+        assert!(saw_complete);
+        assert_eq!(text, "hello\nworld");
+    }
+
+    #[test]
+    fn paste_state_recovers_chars_from_a_lone_esc() {
+        // A bare ESC (e.g. from the Esc key) starts matching the paste-start marker but
+        // then diverges on the very next char; those chars must come back, not vanish.
+        let mut paste = PasteState::default();
+        match paste.feed('\u{1b}') {
+            PasteFeed::Consumed => {}
+            other => panic!("unexpected PasteFeed variant: {:?}", other),
+        }
+        match paste.feed('x') {
+            PasteFeed::Recovered(chars) => assert_eq!(chars, vec!['\u{1b}', 'x']),
+            other => panic!("unexpected PasteFeed variant: {:?}", other),
+        }
+    }
+}